@@ -1,23 +1,173 @@
-use std::collections::btree_map::Entry;
+use std::collections::hash_map::DefaultHasher;
 use std::collections::BTreeMap;
+use std::hash::{Hash, Hasher};
+use std::rc::Rc;
+use std::sync::mpsc;
+use std::thread;
 
-use egui_snarl::Snarl;
+use egui::mutex::Mutex;
+use egui_snarl::{InPinId, NodeId, OutPinId, Snarl};
+use indexmap::IndexMap;
 use mlua::prelude::*;
 use petgraph::algo::toposort;
-use petgraph::graph::DiGraph;
+use petgraph::graph::{DiGraph, NodeIndex};
 use petgraph::visit::EdgeRef;
 use petgraph::Direction;
+use tokio::sync::oneshot;
 
-use crate::node::Node;
+use crate::node::{Control, Node};
 
-pub fn run(lua: &Lua, snarl: &mut Snarl<Node>) -> LuaResult<()> {
+/// Every connection in `snarl`, since `Snarl` only exposes wires pin-by-pin
+/// (there's no single method returning them all).
+fn all_wires(snarl: &Snarl<Node>) -> Vec<(OutPinId, InPinId)> {
+    let mut wires = Vec::new();
+    for (node, data) in snarl.node_ids() {
+        for input in 0..data.inputs().len() {
+            let in_pin = InPinId { node, input };
+            for out_pin in snarl.in_pin(in_pin).remotes {
+                wires.push((out_pin, in_pin));
+            }
+        }
+    }
+    wires
+}
+
+/// Per-node progress of an in-flight [`Run`], surfaced by `show_body`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NodeStatus {
+    Running,
+    Done,
+}
+
+/// A node's cached output, valid as long as `hash` (derived from the node id,
+/// its control values and its upstream input values) doesn't change.
+struct CacheEntry {
+    hash: u64,
+    output: mlua::RegistryKey,
+}
+
+/// Per-node output cache, threaded through [`run_async`] and stored
+/// (non-serialized) alongside `Snarl<Node>` in `State` so it survives between
+/// runs. Invalidate entries with [`invalidate`] when the graph is rewired.
+#[derive(Default)]
+pub struct NodeCache(BTreeMap<NodeId, CacheEntry>);
+
+impl NodeCache {
+    /// Drops the cached entry for `node` and every node reachable from its
+    /// outputs, so a rewire can't leave a stale downstream output in place.
+    pub fn invalidate(&mut self, snarl: &Snarl<Node>, node: NodeId) {
+        let mut stack = vec![node];
+        let mut visited = std::collections::BTreeSet::new();
+        while let Some(node) = stack.pop() {
+            if !visited.insert(node) {
+                continue;
+            }
+            self.0.remove(&node);
+            let Some(n) = snarl.get_node(node) else {
+                continue;
+            };
+            for output in 0..n.outputs().len() {
+                let out_pin = OutPinId { node, output };
+                for in_pin in snarl.out_pin(out_pin).remotes {
+                    stack.push(in_pin.node);
+                }
+            }
+        }
+    }
+}
+
+fn hash_lua_value(value: &LuaValue, hasher: &mut impl Hasher) {
+    match value {
+        LuaValue::Nil => 0u8.hash(hasher),
+        LuaValue::Boolean(b) => b.hash(hasher),
+        LuaValue::Integer(i) => i.hash(hasher),
+        LuaValue::Number(n) => n.to_bits().hash(hasher),
+        LuaValue::Vector(v) => {
+            for lane in [v.x(), v.y(), v.z(), v.w()] {
+                lane.to_bits().hash(hasher);
+            }
+        }
+        LuaValue::String(s) => s.as_bytes().hash(hasher),
+        // Anything else (tables, functions, ...) isn't comparable cheaply, so
+        // treat it as always-dirty rather than risk a false cache hit.
+        _ => {
+            use std::sync::atomic::{AtomicU64, Ordering};
+            static UNHASHABLE: AtomicU64 = AtomicU64::new(0);
+            UNHASHABLE.fetch_add(1, Ordering::Relaxed).hash(hasher)
+        }
+    }
+}
+
+/// Hashes a node's identity together with its (already merged) input table,
+/// i.e. its control values and whatever its upstream nodes delivered.
+fn hash_input(node: NodeId, input: &LuaTable) -> LuaResult<u64> {
+    let mut hasher = DefaultHasher::new();
+    node.hash(&mut hasher);
+
+    let mut entries = input
+        .clone()
+        .pairs::<String, LuaValue>()
+        .collect::<LuaResult<Vec<_>>>()?;
+    entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+    for (name, value) in &entries {
+        name.hash(&mut hasher);
+        hash_lua_value(value, &mut hasher);
+    }
+
+    Ok(hasher.finish())
+}
+
+type RunOutput = LuaResult<(Lua, BTreeMap<NodeId, IndexMap<String, Control>>, NodeCache)>;
+
+/// A graph run driven on a background thread, so it doesn't block
+/// `eframe::App::update`. Poll it with [`Run::poll`] every frame and collect
+/// the outcome with [`Run::finish`] once [`Run::is_finished`] returns `true`.
+pub struct Run {
+    thread: thread::JoinHandle<RunOutput>,
+    status_rx: mpsc::Receiver<(NodeId, NodeStatus)>,
+    pub statuses: BTreeMap<NodeId, NodeStatus>,
+}
+
+impl Run {
+    pub fn is_finished(&self) -> bool {
+        self.thread.is_finished()
+    }
+
+    /// Drains status updates that arrived since the last poll and requests a
+    /// repaint while the run is still in flight.
+    pub fn poll(&mut self, ctx: &egui::Context) {
+        while let Ok((node, status)) = self.status_rx.try_recv() {
+            self.statuses.insert(node, status);
+        }
+        if !self.is_finished() {
+            ctx.request_repaint();
+        }
+    }
+
+    /// Joins the background thread, returning the `Lua` it borrowed and the
+    /// updated controls for every node that ran.
+    pub fn finish(self) -> RunOutput {
+        self.thread
+            .join()
+            .unwrap_or_else(|e| Err(LuaError::external(format!("run thread panicked: {:?}", e))))
+    }
+}
+
+/// Starts a run of `snarl` on a background thread, taking ownership of `lua`
+/// and `cache` for the duration (both are handed back in the result). The
+/// topological order from `petgraph::toposort` is kept, but a node's async
+/// future is only scheduled once every incoming edge has delivered its value,
+/// so independent branches progress concurrently instead of waiting on the
+/// whole graph. A node whose input hash is unchanged from `cache` reuses its
+/// cached output instead of re-running.
+pub fn run_async(lua: Lua, snarl: &Snarl<Node>, cache: NodeCache) -> Run {
     let mut graph = DiGraph::new();
     let mut map = BTreeMap::new();
-    for (node, _) in snarl.node_ids() {
-        map.insert(node, graph.add_node(node));
+    for (id, _) in snarl.node_ids() {
+        map.insert(id, graph.add_node(id));
     }
 
-    for (out_pin, in_pin) in snarl.wires() {
+    for (out_pin, in_pin) in all_wires(snarl) {
         graph.add_edge(
             map[&out_pin.node],
             map[&in_pin.node],
@@ -28,44 +178,227 @@ pub fn run(lua: &Lua, snarl: &mut Snarl<Node>) -> LuaResult<()> {
         );
     }
 
-    let Ok(ord) = toposort(&graph, None) else {
-        return Err(LuaError::external("cycle detected"));
-    };
+    let nodes: BTreeMap<NodeIndex, Node> = snarl
+        .node_ids()
+        .map(|(id, node)| (map[&id], node.clone()))
+        .collect();
+
+    let (status_tx, status_rx) = mpsc::channel();
 
-    let mut inputs = BTreeMap::new();
-    for node in ord {
-        let input = match inputs.entry(node) {
-            Entry::Occupied(input) => input.into_mut(),
-            Entry::Vacant(input) => input.insert(lua.create_table()?),
+    let thread = thread::spawn(move || -> RunOutput {
+        let Ok(order) = toposort(&graph, None) else {
+            return Err(LuaError::external("cycle detected"));
         };
 
-        let snarl_node = &mut snarl[graph[node]];
-        for (name, data) in &snarl_node.data {
-            input.set(name.as_str(), data.get_value(lua)?)?;
-        }
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .map_err(LuaError::external)?;
+        let local = tokio::task::LocalSet::new();
+        let cache = Rc::new(Mutex::new(cache));
+        // `Lua` can be moved across threads but never cloned or shared by
+        // reference across them; since every task below runs on this same
+        // background thread via `spawn_local`, an `Rc` is enough to hand each
+        // of them a handle without needing `Lua: Sync`.
+        let lua = Rc::new(lua);
+
+        let controls = local.block_on(&runtime, async {
+            // One oneshot channel per edge, carrying the upstream value once
+            // its node has run; receivers are grouped by the edge's target so
+            // a node can await exactly the edges feeding it. The value travels
+            // as a `RegistryKey` rather than a `LuaValue`, since the latter
+            // borrows from `Lua` and can't be held across the `'static`
+            // boundary `spawn_local` futures require.
+            let mut senders = BTreeMap::new();
+            let mut receivers: BTreeMap<NodeIndex, Vec<(String, oneshot::Receiver<mlua::RegistryKey>)>> =
+                BTreeMap::new();
+            for edge in graph.edge_indices() {
+                let (tx, rx) = oneshot::channel();
+                senders.insert(edge, tx);
+                let (_, target) = graph.edge_endpoints(edge).unwrap();
+                let in_name = graph[edge].1.clone();
+                receivers.entry(target).or_default().push((in_name, rx));
+            }
+
+            let mut tasks = Vec::new();
+            for &node in &order {
+                let node_id = graph[node];
+                let mut data = nodes[&node].clone();
+                let incoming = receivers.remove(&node).unwrap_or_default();
+                let outgoing: Vec<_> = graph
+                    .edges_directed(node, Direction::Outgoing)
+                    .map(|e| (e.weight().0.clone(), senders.remove(&e.id()).unwrap()))
+                    .collect();
+                let lua = lua.clone();
+                let status_tx = status_tx.clone();
+                let cache = cache.clone();
+
+                tasks.push(tokio::task::spawn_local(async move {
+                    let input = lua.create_table()?;
+                    for (name, control) in &data.data {
+                        input.set(name.as_str(), control.get_value(&lua)?)?;
+                    }
+                    for (in_name, rx) in incoming {
+                        let key = rx
+                            .await
+                            .map_err(|_| LuaError::external("upstream node failed"))?;
+                        let value: LuaValue = lua.registry_value(&key)?;
+                        input.set(in_name.as_str(), value)?;
+                    }
+
+                    let hash = hash_input(node_id, &input)?;
+                    // Stateful nodes may produce a different output on every
+                    // run even with byte-identical inputs, so never serve
+                    // them from the cache.
+                    // `RegistryKey` isn't `Clone`, so look the cached value up
+                    // and resolve it to a `LuaTable` while still holding the
+                    // lock, rather than cloning the key out of it.
+                    let cached = if data.has_state() {
+                        None
+                    } else {
+                        cache
+                            .lock()
+                            .0
+                            .get(&node_id)
+                            .filter(|entry| entry.hash == hash)
+                            .map(|entry| lua.registry_value::<LuaTable>(&entry.output))
+                            .transpose()?
+                    };
+
+                    let output = if let Some(output) = cached {
+                        status_tx.send((node_id, NodeStatus::Done)).ok();
+                        output
+                    } else {
+                        status_tx.send((node_id, NodeStatus::Running)).ok();
+                        let output = data.run_async(&lua, input).await?;
+
+                        for (name, control) in &mut data.data {
+                            let value: LuaValue = output.get(name.as_str())?;
+                            if !value.is_nil() {
+                                control.set_value(&lua, value)?;
+                            }
+                        }
 
-        let output = snarl_node.run(lua, input.clone())?;
+                        if !data.has_state() {
+                            let key = lua.create_registry_value(output.clone())?;
+                            cache.lock().0.insert(node_id, CacheEntry { hash, output: key });
+                        }
+                        status_tx.send((node_id, NodeStatus::Done)).ok();
+                        output
+                    };
 
-        for (name, data) in &mut snarl_node.data {
-            let value: LuaValue = output.get(name.as_str())?;
-            if !value.is_nil() {
-                data.set_value(lua, value)?;
+                    for (out_name, tx) in outgoing {
+                        let value: LuaValue = output.get(out_name.as_str())?;
+                        tx.send(lua.create_registry_value(value)?).ok();
+                    }
+
+                    Ok::<_, LuaError>((node, data.data))
+                }));
             }
-        }
 
-        for edge in graph.edges_directed(node, Direction::Outgoing) {
-            let next = edge.target();
-            let (out_name, in_name) = edge.weight();
+            let mut controls = BTreeMap::new();
+            for task in tasks {
+                let (node, data) = task
+                    .await
+                    .map_err(|e| LuaError::external(e.to_string()))??;
+                controls.insert(node, data);
+            }
+            Ok::<_, LuaError>(controls)
+        })?;
 
-            let input = match inputs.entry(next) {
-                Entry::Occupied(input) => input.into_mut(),
-                Entry::Vacant(input) => input.insert(lua.create_table()?),
-            };
-            let out: LuaValue = output.get(out_name.as_str())?;
+        let by_id = controls.into_iter().map(|(idx, data)| (graph[idx], data)).collect();
+        let cache = std::mem::take(&mut *cache.lock());
+        let lua = Rc::try_unwrap(lua)
+            .unwrap_or_else(|_| panic!("node tasks should have dropped their Lua handle by now"));
+        Ok((lua, by_id, cache))
+    });
 
-            input.set(in_name.as_str(), out)?;
-        }
+    Run {
+        thread,
+        status_rx,
+        statuses: BTreeMap::new(),
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use egui::Pos2;
 
-    Ok(())
+    use super::*;
+    use crate::node::{NodePrototype, Pin};
+
+    fn test_node(inputs: &[&str], outputs: &[&str]) -> Node {
+        let prototype = NodePrototype {
+            inputs: inputs.iter().map(|name| (name.to_string(), Pin::Float)).collect(),
+            outputs: outputs.iter().map(|name| (name.to_string(), Pin::Float)).collect(),
+            ..NodePrototype::unknown("test".to_string())
+        };
+        Arc::new(prototype).create()
+    }
+
+    #[test]
+    fn hash_input_is_stable_for_unchanged_values() {
+        let lua = Lua::new();
+        let input = lua.create_table().unwrap();
+        input.set("x", 1.0).unwrap();
+        input.set("y", "same").unwrap();
+
+        let node = NodeId(0);
+        assert_eq!(hash_input(node, &input).unwrap(), hash_input(node, &input).unwrap());
+    }
+
+    #[test]
+    fn hash_input_changes_with_a_value_or_the_node_id() {
+        let lua = Lua::new();
+        let input = lua.create_table().unwrap();
+        input.set("x", 1.0).unwrap();
+
+        let base = hash_input(NodeId(0), &input).unwrap();
+
+        input.set("x", 2.0).unwrap();
+        assert_ne!(base, hash_input(NodeId(0), &input).unwrap());
+
+        input.set("x", 1.0).unwrap();
+        assert_ne!(base, hash_input(NodeId(1), &input).unwrap());
+    }
+
+    #[test]
+    fn hash_input_treats_nested_tables_as_always_dirty() {
+        let lua = Lua::new();
+        let input = lua.create_table().unwrap();
+        input.set("nested", lua.create_table().unwrap()).unwrap();
+
+        let node = NodeId(0);
+        // A value mlua can't hash cheaply (e.g. a table) must never produce a
+        // false cache hit, so two calls with "the same" nested table still
+        // disagree.
+        assert_ne!(hash_input(node, &input).unwrap(), hash_input(node, &input).unwrap());
+    }
+
+    #[test]
+    fn invalidate_drops_the_node_and_everything_downstream() {
+        let lua = Lua::new();
+        let mut snarl = Snarl::<Node>::new();
+        let a = snarl.insert_node(Pos2::ZERO, test_node(&[], &["out"]));
+        let b = snarl.insert_node(Pos2::ZERO, test_node(&["in"], &["out"]));
+        let c = snarl.insert_node(Pos2::ZERO, test_node(&["in"], &[]));
+        let d = snarl.insert_node(Pos2::ZERO, test_node(&[], &[]));
+
+        snarl.connect(OutPinId { node: a, output: 0 }, InPinId { node: b, input: 0 });
+        snarl.connect(OutPinId { node: b, output: 0 }, InPinId { node: c, input: 0 });
+
+        let mut cache = NodeCache::default();
+        for node in [a, b, c, d] {
+            let key = lua.create_registry_value(lua.create_table().unwrap()).unwrap();
+            cache.0.insert(node, CacheEntry { hash: 0, output: key });
+        }
+
+        cache.invalidate(&snarl, a);
+
+        assert!(!cache.0.contains_key(&a));
+        assert!(!cache.0.contains_key(&b));
+        assert!(!cache.0.contains_key(&c));
+        assert!(cache.0.contains_key(&d));
+    }
 }
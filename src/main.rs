@@ -1,15 +1,18 @@
 #![allow(dead_code)]
 
+use std::collections::BTreeMap;
 use std::fmt::Display;
-use std::sync::Arc;
+use std::sync::{mpsc, Arc};
 
 use eframe::{CreationContext, NativeOptions};
 use egui::Label;
 use egui_snarl::ui::{PinInfo, SnarlStyle, SnarlViewer};
 use egui_snarl::{InPin, NodeId, OutPin, Snarl};
 use mlua::Lua;
+use notify::RecommendedWatcher;
 use serde::{Deserialize, Serialize};
 
+use crate::engine::{NodeCache, NodeStatus, Run};
 use crate::node::Node;
 
 mod engine;
@@ -20,6 +23,10 @@ mod script;
 struct State {
     snarl: Snarl<Node>,
     snarl_style: SnarlStyle,
+    /// Per-node output cache for incremental re-evaluation; not persisted,
+    /// since a reloaded graph hasn't run yet.
+    #[serde(skip)]
+    node_cache: NodeCache,
 }
 
 impl Default for State {
@@ -27,18 +34,31 @@ impl Default for State {
         Self {
             snarl: Snarl::new(),
             snarl_style: SnarlStyle::default(),
+            node_cache: NodeCache::default(),
         }
     }
 }
 
 struct App {
     state: State,
-    lua: Lua,
+    /// `None` while a run has taken ownership of the `Lua` on its background
+    /// thread; restored by `update` once that run finishes.
+    lua: Option<Lua>,
+    run: Option<Run>,
+    statuses: BTreeMap<NodeId, NodeStatus>,
+    /// Kept alive so `scripts/` keeps being watched; never read otherwise.
+    _script_watcher: RecommendedWatcher,
+    script_reload_rx: mpsc::Receiver<()>,
+    /// Set when a script change arrives while `lua` is off on a run's
+    /// background thread; applied once it comes back in `update`.
+    script_reload_pending: bool,
 }
 
 impl App {
     fn create(cc: &CreationContext) -> Box<dyn eframe::App> {
         let lua = script::init_lua().unwrap_or_else(|e| panic!("{}", e));
+        let (script_watcher, script_reload_rx) =
+            script::watch_scripts().unwrap_or_else(|e| panic!("{}", e));
 
         let state = cc
             .storage
@@ -46,24 +66,86 @@ impl App {
             .and_then(|s| ron::from_str(&s).inspect_err(report_error).ok())
             .unwrap_or_default();
 
-        Box::new(App { state, lua })
+        Box::new(App {
+            state,
+            lua: Some(lua),
+            run: None,
+            statuses: BTreeMap::new(),
+            _script_watcher: script_watcher,
+            script_reload_rx,
+            script_reload_pending: false,
+        })
+    }
+
+    /// Rebinds every placed node to its (possibly just-reloaded) prototype,
+    /// preserving control values and accumulated state, and falling back to
+    /// `NodePrototype::unknown` if the node's id no longer has a registered
+    /// factory. Reloading one script shouldn't reset the persistent state of
+    /// unrelated nodes, so this uses `Node::rebind` rather than routing
+    /// through `Node::from_data`.
+    fn rebind_nodes(&mut self) {
+        let ids: Vec<NodeId> = self.state.snarl.node_ids().map(|(id, _)| id).collect();
+        for id in ids {
+            self.state.snarl[id].rebind();
+            self.state.node_cache.invalidate(&self.state.snarl, id);
+        }
     }
 }
 
 impl eframe::App for App {
     fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
+        if self.script_reload_rx.try_recv().is_ok() {
+            self.script_reload_pending = true;
+        }
+        if self.script_reload_pending {
+            if let Some(lua) = &self.lua {
+                match script::reload_scripts(lua) {
+                    Ok(()) => self.rebind_nodes(),
+                    Err(e) => report_error(&e),
+                }
+                self.script_reload_pending = false;
+            }
+        }
+
+        if let Some(run) = &mut self.run {
+            run.poll(ctx);
+            self.statuses = run.statuses.clone();
+            if run.is_finished() {
+                match self.run.take().unwrap().finish() {
+                    Ok((lua, outputs, cache)) => {
+                        for (id, data) in outputs {
+                            if let Some(node) = self.state.snarl.get_node_mut(id) {
+                                node.data = data;
+                            }
+                        }
+                        self.lua = Some(lua);
+                        self.state.node_cache = cache;
+                    }
+                    Err(e) => report_error(&e),
+                }
+                self.statuses.clear();
+            }
+        }
+
         egui::TopBottomPanel::top("menu").show(ctx, |ui| {
             egui::menu::bar(ui, |ui| {
-                if ui.button("Run").clicked() {
-                    if let Err(e) = engine::run(&self.lua, &mut self.state.snarl) {
-                        report_error(&e);
-                    }
+                let running = self.run.is_some();
+                if ui
+                    .add_enabled(!running, egui::Button::new("Run"))
+                    .clicked()
+                {
+                    let lua = self.lua.take().expect("lua is available when not running");
+                    let cache = std::mem::take(&mut self.state.node_cache);
+                    self.run = Some(engine::run_async(lua, &self.state.snarl, cache));
                 }
             });
         });
         egui::CentralPanel::default().show(ctx, |ui| {
             self.state.snarl.show(
-                &mut Viewer,
+                &mut Viewer {
+                    statuses: &self.statuses,
+                    node_cache: &mut self.state.node_cache,
+                },
                 &self.state.snarl_style,
                 egui::Id::new("editor"),
                 ui,
@@ -77,9 +159,12 @@ impl eframe::App for App {
     }
 }
 
-struct Viewer;
+struct Viewer<'a> {
+    statuses: &'a BTreeMap<NodeId, NodeStatus>,
+    node_cache: &'a mut NodeCache,
+}
 
-impl SnarlViewer<Node> for Viewer {
+impl SnarlViewer<Node> for Viewer<'_> {
     fn title(&mut self, node: &Node) -> String {
         node.title().to_string()
     }
@@ -100,7 +185,9 @@ impl SnarlViewer<Node> for Viewer {
         // The pins must have the same type.
         let from_pins = snarl[from.id.node].outputs();
         let to_pins = snarl[to.id.node].inputs();
-        if from_pins[from.id.output] != to_pins[to.id.input] {
+        let from_pin = from_pins.get_index(from.id.output).unwrap().1;
+        let to_pin = to_pins.get_index(to.id.input).unwrap().1;
+        if from_pin != to_pin {
             return;
         }
 
@@ -109,6 +196,12 @@ impl SnarlViewer<Node> for Viewer {
             snarl.disconnect(remote, to.id);
         }
         snarl.connect(from.id, to.id);
+        self.node_cache.invalidate(snarl, to.id.node);
+    }
+
+    fn disconnect(&mut self, from: &OutPin, to: &InPin, snarl: &mut Snarl<Node>) {
+        snarl.disconnect(from.id, to.id);
+        self.node_cache.invalidate(snarl, to.id.node);
     }
 
     fn outputs(&mut self, node: &Node) -> usize {
@@ -146,7 +239,7 @@ impl SnarlViewer<Node> for Viewer {
     }
 
     fn has_body(&mut self, node: &Node) -> bool {
-        !node.data.is_empty()
+        !node.data.is_empty() || !self.statuses.is_empty()
     }
 
     fn show_body(
@@ -160,6 +253,18 @@ impl SnarlViewer<Node> for Viewer {
     ) {
         ui.vertical(|ui| {
             ui.allocate_space(egui::vec2(100.0, 0.0));
+            match self.statuses.get(&node) {
+                Some(NodeStatus::Running) => {
+                    ui.horizontal(|ui| {
+                        ui.spinner();
+                        ui.label("Running");
+                    });
+                }
+                Some(NodeStatus::Done) => {
+                    ui.label("Done");
+                }
+                None => {}
+            }
             for (name, control) in &mut snarl[node].data {
                 ui.label(name);
                 ui.end_row();
@@ -168,17 +273,7 @@ impl SnarlViewer<Node> for Viewer {
         });
     }
 
-    fn has_graph_menu(&mut self, _pos: egui::Pos2, _snarl: &mut Snarl<Node>) -> bool {
-        true
-    }
-
-    fn show_graph_menu(
-        &mut self,
-        pos: egui::Pos2,
-        ui: &mut egui::Ui,
-        _scale: f32,
-        snarl: &mut Snarl<Node>,
-    ) {
+    fn graph_menu(&mut self, pos: egui::Pos2, ui: &mut egui::Ui, _scale: f32, snarl: &mut Snarl<Node>) {
         ui.label("New node");
         for (name, entry) in &*script::CATEGORY.lock() {
             entry.menu(name, ui, &mut |prototype| {
@@ -187,11 +282,7 @@ impl SnarlViewer<Node> for Viewer {
         }
     }
 
-    fn has_node_menu(&mut self, _node: &Node) -> bool {
-        true
-    }
-
-    fn show_node_menu(
+    fn node_menu(
         &mut self,
         node: NodeId,
         _inputs: &[InPin],
@@ -201,17 +292,34 @@ impl SnarlViewer<Node> for Viewer {
         snarl: &mut Snarl<Node>,
     ) {
         if ui.button("Delete").clicked() {
+            self.node_cache.invalidate(snarl, node);
             snarl.remove_node(node);
             ui.close_menu();
         }
 
         if ui.button("Clone").clicked() {
-            let (node, &pos) = snarl.get_node_pos(node).unwrap();
+            let pos = snarl
+                .nodes_pos_ids()
+                .find(|&(id, _, _)| id == node)
+                .map(|(_, pos, _)| pos)
+                .unwrap();
             let pos = pos + egui::vec2(10.0, 10.0);
-            snarl.insert_node(pos, node.clone());
+            let mut cloned = snarl[node].clone();
+            cloned.reset_state();
+            snarl.insert_node(pos, cloned);
             ui.close_menu();
         }
     }
+
+    fn input_color(&mut self, pin: &InPin, _style: &egui::Style, snarl: &mut Snarl<Node>) -> egui::Color32 {
+        let node = &snarl[pin.id.node];
+        node.inputs().get_index(pin.id.input).unwrap().1.info().fill
+    }
+
+    fn output_color(&mut self, pin: &OutPin, _style: &egui::Style, snarl: &mut Snarl<Node>) -> egui::Color32 {
+        let node = &snarl[pin.id.node];
+        node.outputs().get_index(pin.id.output).unwrap().1.info().fill
+    }
 }
 
 pub fn report_error(e: &(impl Display + ?Sized)) {
@@ -224,7 +332,17 @@ pub fn report_error(e: &(impl Display + ?Sized)) {
 }
 
 fn main() -> Result<(), eframe::Error> {
-    std::panic::set_hook(Box::new(|pi| {
+    let main_thread = std::thread::current().id();
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |pi| {
+        default_hook(pi);
+        // A panic on a background run thread (see engine::run_async) is
+        // caught by Run::finish and reported as a recoverable LuaError; only
+        // force-exit for a panic on the main thread, or this hook would kill
+        // the whole process before that recovery path ever runs.
+        if std::thread::current().id() != main_thread {
+            return;
+        }
         report_error(pi);
         std::process::exit(1);
     }));
@@ -1,19 +1,42 @@
 use std::cell::OnceCell;
 use std::sync::Arc;
 
+use egui::mutex::Mutex;
 use egui::Color32;
 use egui_snarl::ui::PinInfo;
 use indexmap::IndexMap;
 use mlua::prelude::*;
+use mlua::Vector;
 use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Clone)]
 pub struct Node {
     prototype: Arc<NodePrototype>,
     pub data: IndexMap<String, Control>,
+    /// Present only when the prototype declared a `state` field; shared with
+    /// the running Lua script so e.g. integrators and counters can persist
+    /// across "Run" invocations. `Node::clone` shares this state with the
+    /// source node and `rebind` keeps it across script reloads; the node
+    /// menu's Clone action gives the copy its own fresh state via
+    /// `reset_state`.
+    state: Option<NodeState>,
 }
 
 impl Node {
+    pub fn id(&self) -> &str {
+        &self.prototype.id
+    }
+
+    pub fn has_state(&self) -> bool {
+        self.state.is_some()
+    }
+
+    /// Replaces this node's state with a fresh copy of the prototype's
+    /// initial value, dropping any accumulated state.
+    pub fn reset_state(&mut self) {
+        self.state = self.prototype.state.clone().map(NodeState::fresh);
+    }
+
     pub fn title(&self) -> &str {
         &self.prototype.title
     }
@@ -34,14 +57,24 @@ impl Node {
         self.prototype.outputs.get_index(index).unwrap().0
     }
 
-    pub fn run<'a>(&self, lua: &'a Lua, input: LuaTable<'a>) -> LuaResult<LuaTable<'a>> {
-        (self.prototype.run)(lua, input)
+    /// Runs the node's Lua function, driven via `call_async` so that nodes
+    /// backed by an `async function` can yield without blocking the
+    /// scheduler. Plain functions simply resolve on the first poll. A node
+    /// with a `state` field gets its persistent state as a second argument.
+    pub fn run_async<'a>(&self, lua: &'a Lua, input: LuaTable<'a>) -> NodeFuture<'a> {
+        (self.prototype.run)(lua, input, self.state.clone())
     }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, FromLua)]
 pub enum Pin {
     Float,
+    Vec2,
+    Vec3,
+    Vec4,
+    /// Carries an arbitrary Lua value (typically a table) untouched between
+    /// nodes, rather than a single scalar.
+    Any,
 }
 
 impl mlua::UserData for Pin {}
@@ -50,14 +83,196 @@ impl Pin {
     pub fn info(&self) -> PinInfo {
         match self {
             Pin::Float => PinInfo::square().with_fill(Color32::LIGHT_BLUE),
+            Pin::Vec2 => PinInfo::square().with_fill(Color32::LIGHT_GREEN),
+            Pin::Vec3 => PinInfo::square().with_fill(Color32::LIGHT_YELLOW),
+            Pin::Vec4 => PinInfo::square().with_fill(Color32::LIGHT_RED),
+            Pin::Any => PinInfo::square().with_fill(Color32::GRAY),
         }
     }
 }
 
+/// An owned, serializable snapshot of a Lua value, used to persist and
+/// render whatever a `Pin::Any` wire last carried.
+#[derive(Debug, Default, Serialize, Deserialize, Clone, PartialEq)]
+pub enum TableValue {
+    #[default]
+    Nil,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<TableValue>),
+    Table(IndexMap<String, TableValue>),
+}
+
+/// How many levels of nested table a `TableValue` will follow before giving
+/// up and treating the remainder as opaque, same as an unsupported Lua
+/// value. Generous enough for any real script's data shape, but well short
+/// of blowing the stack on a pathological one.
+const MAX_TABLE_DEPTH: usize = 64;
+
+impl TableValue {
+    pub fn from_lua(value: &LuaValue) -> LuaResult<TableValue> {
+        Self::from_lua_impl(value, &mut Vec::new(), 0)
+    }
+
+    /// `visited` holds the pointers of tables currently being converted
+    /// higher up the call stack, so a self-referential table (e.g. `t = {};
+    /// t.x = t`) is caught rather than recursed into forever; `depth` caps
+    /// how deep nested-but-acyclic tables are followed. Either limit makes a
+    /// table fall back to opaque debug text, same as a function/userdata/etc.
+    fn from_lua_impl(
+        value: &LuaValue,
+        visited: &mut Vec<*const std::ffi::c_void>,
+        depth: usize,
+    ) -> LuaResult<TableValue> {
+        Ok(match value {
+            LuaValue::Nil => TableValue::Nil,
+            LuaValue::Boolean(b) => TableValue::Bool(*b),
+            LuaValue::Integer(i) => TableValue::Number(*i as f64),
+            LuaValue::Number(n) => TableValue::Number(*n),
+            LuaValue::String(s) => TableValue::String(s.to_str()?.to_string()),
+            LuaValue::Table(table) => {
+                let ptr = table.to_pointer();
+                if depth >= MAX_TABLE_DEPTH || visited.contains(&ptr) {
+                    return Ok(TableValue::String(format!("{value:?}")));
+                }
+                visited.push(ptr);
+
+                let len = table.raw_len();
+                let is_array = len > 0 && table.clone().pairs::<LuaValue, LuaValue>().count() == len;
+                let result = if is_array {
+                    let mut array = Vec::with_capacity(len);
+                    for i in 1..=len {
+                        array.push(Self::from_lua_impl(&table.get(i)?, visited, depth + 1)?);
+                    }
+                    TableValue::Array(array)
+                } else {
+                    // Keys aren't necessarily strings (e.g. a table mixing a
+                    // positional prefix with named fields), so stringify
+                    // non-string keys instead of erroring on them.
+                    let mut map = IndexMap::new();
+                    for pair in table.clone().pairs::<LuaValue, LuaValue>() {
+                        let (key, value) = pair?;
+                        let key = match key {
+                            LuaValue::String(s) => s.to_str()?.to_string(),
+                            LuaValue::Integer(i) => i.to_string(),
+                            LuaValue::Number(n) => n.to_string(),
+                            other => format!("{other:?}"),
+                        };
+                        map.insert(key, Self::from_lua_impl(&value, visited, depth + 1)?);
+                    }
+                    TableValue::Table(map)
+                };
+
+                visited.pop();
+                result
+            }
+            // Functions, userdata, threads, ... don't have a useful owned
+            // form; show them as opaque debug text rather than failing.
+            other => TableValue::String(format!("{other:?}")),
+        })
+    }
+
+    pub fn to_lua<'a>(&self, lua: &'a Lua) -> LuaResult<LuaValue<'a>> {
+        Ok(match self {
+            TableValue::Nil => LuaValue::Nil,
+            TableValue::Bool(b) => LuaValue::Boolean(*b),
+            TableValue::Number(n) => LuaValue::Number(*n),
+            TableValue::String(s) => LuaValue::String(lua.create_string(s)?),
+            TableValue::Array(items) => {
+                let table = lua.create_table()?;
+                for (i, item) in items.iter().enumerate() {
+                    table.set(i + 1, item.to_lua(lua)?)?;
+                }
+                LuaValue::Table(table)
+            }
+            TableValue::Table(map) => {
+                let table = lua.create_table()?;
+                for (key, item) in map {
+                    table.set(key.as_str(), item.to_lua(lua)?)?;
+                }
+                LuaValue::Table(table)
+            }
+        })
+    }
+}
+
+/// Renders a `TableValue` as a collapsible tree, recursing into arrays and
+/// tables.
+fn show_table_value(ui: &mut egui::Ui, value: &TableValue) {
+    match value {
+        TableValue::Nil => {
+            ui.label("nil");
+        }
+        TableValue::Bool(b) => {
+            ui.label(b.to_string());
+        }
+        TableValue::Number(n) => {
+            ui.label(format!("{n:.2}"));
+        }
+        TableValue::String(s) => {
+            ui.label(format!("{s:?}"));
+        }
+        TableValue::Array(items) => {
+            ui.collapsing(format!("[{}]", items.len()), |ui| {
+                for (index, item) in items.iter().enumerate() {
+                    ui.horizontal(|ui| {
+                        ui.label(format!("{index}:"));
+                        show_table_value(ui, item);
+                    });
+                }
+            });
+        }
+        TableValue::Table(map) => {
+            ui.collapsing(format!("{{{}}}", map.len()), |ui| {
+                for (key, item) in map {
+                    ui.horizontal(|ui| {
+                        ui.label(format!("{key}:"));
+                        show_table_value(ui, item);
+                    });
+                }
+            });
+        }
+    }
+}
+
+/// A node's persistent state: a `TableValue` shared between runs, exposed to
+/// Lua as userdata with `:get()`/`:set(value)` methods rather than a plain
+/// table, since mlua can't hand out a table that outlives a single `Lua`
+/// borrow. Cheap to clone (shares the `Arc`), but a clone shares the *same*
+/// underlying state — use `fresh` to start a new node off independently.
+#[derive(Clone)]
+pub struct NodeState(Arc<Mutex<TableValue>>);
+
+impl NodeState {
+    fn fresh(initial: TableValue) -> NodeState {
+        NodeState(Arc::new(Mutex::new(initial)))
+    }
+}
+
+impl std::fmt::Debug for NodeState {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str("NodeState(..)")
+    }
+}
+
+impl mlua::UserData for NodeState {
+    fn add_methods<'lua, M: mlua::UserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_method("get", |lua, this, ()| this.0.lock().to_lua(lua));
+        methods.add_method("set", |_lua, this, value: LuaValue| {
+            *this.0.lock() = TableValue::from_lua(&value)?;
+            Ok(())
+        });
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone, FromLua)]
 pub enum Control {
     Slider { value: f32, min: f32, max: f32 },
     ShowFloat { value: f32 },
+    VectorInput { value: [f32; 4], dim: u8 },
+    ShowVector { value: [f32; 4], dim: u8 },
+    ShowValue { value: TableValue },
 }
 
 impl mlua::UserData for Control {}
@@ -71,6 +286,22 @@ impl Control {
             Control::ShowFloat { value } => {
                 ui.label(format!("{:.2}", value));
             }
+            Control::VectorInput { value, dim } => {
+                ui.horizontal(|ui| {
+                    for component in &mut value[..*dim as usize] {
+                        ui.add(egui::DragValue::new(component).speed(0.01));
+                    }
+                });
+            }
+            Control::ShowVector { value, dim } => {
+                let text = value[..*dim as usize]
+                    .iter()
+                    .map(|v| format!("{:.2}", v))
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                ui.label(text);
+            }
+            Control::ShowValue { value } => show_table_value(ui, value),
         }
     }
 
@@ -78,6 +309,10 @@ impl Control {
         match self {
             Control::Slider { value, .. } => value.into_lua(lua),
             Control::ShowFloat { value } => value.into_lua(lua),
+            Control::VectorInput { value, dim } | Control::ShowVector { value, dim } => {
+                vector_from_components(value, *dim).into_lua(lua)
+            }
+            Control::ShowValue { value } => value.to_lua(lua),
         }
     }
 
@@ -85,11 +320,35 @@ impl Control {
         match self {
             Control::Slider { value, .. } => *value = f32::from_lua(lua_value, lua)?,
             Control::ShowFloat { value } => *value = f32::from_lua(lua_value, lua)?,
+            Control::VectorInput { value, dim } | Control::ShowVector { value, dim } => {
+                let vector = Vector::from_lua(lua_value, lua)?;
+                *value = components_from_vector(&vector, *dim);
+            }
+            Control::ShowValue { value } => *value = TableValue::from_lua(&lua_value)?,
         }
         Ok(())
     }
 }
 
+/// Builds a Luau native vector from the first `dim` components, zero-filling
+/// the remaining lanes (e.g. the `w` lane of a `Vec3`).
+fn vector_from_components(value: &[f32; 4], dim: u8) -> Vector {
+    let get = |i: usize| if (i as u8) < dim { value[i] } else { 0.0 };
+    Vector::new(get(0), get(1), get(2), get(3))
+}
+
+/// Reads back the first `dim` lanes of a Luau native vector, dropping the rest.
+fn components_from_vector(vector: &Vector, dim: u8) -> [f32; 4] {
+    let lanes = [vector.x(), vector.y(), vector.z(), vector.w()];
+    let mut value = [0.0; 4];
+    value[..dim as usize].copy_from_slice(&lanes[..dim as usize]);
+    value
+}
+
+/// A node's Lua `run` function, boxed so it can be driven to completion with
+/// `.await` regardless of whether the underlying Lua function is async.
+pub type NodeFuture<'a> = std::pin::Pin<Box<dyn std::future::Future<Output = LuaResult<LuaTable<'a>>> + 'a>>;
+
 #[allow(clippy::type_complexity)]
 pub struct NodePrototype {
     pub id: String,
@@ -97,7 +356,11 @@ pub struct NodePrototype {
     pub inputs: IndexMap<String, Pin>,
     pub outputs: IndexMap<String, Pin>,
     pub controls: IndexMap<String, Control>,
-    pub run: Box<dyn for<'a> Fn(&'a Lua, LuaTable<'a>) -> LuaResult<LuaTable<'a>> + Send + Sync>,
+    /// Initial value for a node's persistent `NodeState`, if the script
+    /// registered one. `None` for stateless nodes (the common case).
+    pub state: Option<TableValue>,
+    pub run:
+        Box<dyn for<'a> Fn(&'a Lua, LuaTable<'a>, Option<NodeState>) -> NodeFuture<'a> + Send + Sync>,
 }
 
 impl std::fmt::Debug for NodePrototype {
@@ -121,15 +384,20 @@ impl NodePrototype {
             inputs: IndexMap::new(),
             outputs: IndexMap::new(),
             controls: IndexMap::new(),
-            run: Box::new(|_, _| Err(LuaError::external("unknown node type"))),
+            state: None,
+            run: Box::new(|_, _, _| {
+                Box::pin(async { Err(LuaError::external("unknown node type")) })
+            }),
         }
     }
 
     pub fn create(self: Arc<Self>) -> Node {
         let data = self.controls.clone();
+        let state = self.state.clone().map(NodeState::fresh);
         Node {
             prototype: self,
             data,
+            state,
         }
     }
 }
@@ -149,12 +417,59 @@ impl Node {
                 return Node {
                     data,
                     prototype: Arc::new(NodePrototype::unknown(id)),
+                    state: None,
                 };
             };
             // TODO: check that the controls match the factory's controls
-            Node { data, prototype }
+            let state = prototype.state.clone().map(NodeState::fresh);
+            Node {
+                data,
+                prototype,
+                state,
+            }
         })
     }
+
+    /// Re-resolves this node's prototype against the (possibly just-reloaded)
+    /// registry, keeping `data` and, where still applicable, `state` in
+    /// place. Unlike `from_data`, this doesn't reset a node's accumulated
+    /// state just because *some* script was reloaded — only a node whose own
+    /// prototype stopped/started declaring a `state` field has its state
+    /// cleared/initialized.
+    pub fn rebind(&mut self) {
+        let id = self.prototype.id.clone();
+        self.prototype = Node::find_factory(id.clone(), |factory| {
+            factory.unwrap_or_else(|| Arc::new(NodePrototype::unknown(id)))
+        });
+        match (&self.state, &self.prototype.state) {
+            (None, Some(initial)) => self.state = Some(NodeState::fresh(initial.clone())),
+            (Some(_), None) => self.state = None,
+            _ => {}
+        }
+        self.rebind_controls();
+    }
+
+    /// Reconciles `self.data` against the (possibly just-reloaded)
+    /// prototype's controls: a control the script newly declared gets the
+    /// prototype's default, one the script dropped is removed, and one whose
+    /// stored variant no longer matches the prototype's (e.g. `slider` ->
+    /// `vector_input`) is reset to the default rather than kept mismatched.
+    fn rebind_controls(&mut self) {
+        self.data = self
+            .prototype
+            .controls
+            .iter()
+            .map(|(name, default)| {
+                let value = match self.data.get(name) {
+                    Some(value) if std::mem::discriminant(value) == std::mem::discriminant(default) => {
+                        value.clone()
+                    }
+                    _ => default.clone(),
+                };
+                (name.clone(), value)
+            })
+            .collect();
+    }
 }
 
 impl Serialize for Node {
@@ -216,3 +531,88 @@ impl<'de> Deserialize<'de> for Node {
         deserializer.deserialize_struct("Node", &["id", "controls"], NodeVisitor)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn from_lua_distinguishes_array_from_map() {
+        let lua = Lua::new();
+        let array: LuaValue = lua.load("return {10, 20, 30}").eval().unwrap();
+        assert!(matches!(
+            TableValue::from_lua(&array).unwrap(),
+            TableValue::Array(items) if items.len() == 3
+        ));
+
+        let map: LuaValue = lua.load("return {x = 1, y = 2}").eval().unwrap();
+        let TableValue::Table(map) = TableValue::from_lua(&map).unwrap() else {
+            panic!("expected a TableValue::Table");
+        };
+        assert_eq!(map.get("x"), Some(&TableValue::Number(1.0)));
+        assert_eq!(map.get("y"), Some(&TableValue::Number(2.0)));
+    }
+
+    #[test]
+    fn from_lua_stringifies_non_string_keys() {
+        let lua = Lua::new();
+        // Mixing a positional entry with a named one forces the non-array
+        // path, where the positional key is an integer rather than a string.
+        let value: LuaValue = lua.load("return {[1] = 'a', named = 'b'}").eval().unwrap();
+        let TableValue::Table(map) = TableValue::from_lua(&value).unwrap() else {
+            panic!("expected a TableValue::Table");
+        };
+        assert_eq!(map.get("1"), Some(&TableValue::String("a".to_string())));
+        assert_eq!(map.get("named"), Some(&TableValue::String("b".to_string())));
+    }
+
+    #[test]
+    fn from_lua_round_trips_through_to_lua() {
+        let lua = Lua::new();
+        let original: LuaValue = lua
+            .load("return {1, 2, {nested = true, list = {'a', 'b'}}}")
+            .eval()
+            .unwrap();
+        let snapshot = TableValue::from_lua(&original).unwrap();
+        let restored = snapshot.to_lua(&lua).unwrap();
+        let roundtripped = TableValue::from_lua(&restored).unwrap();
+        assert_eq!(snapshot, roundtripped);
+    }
+
+    #[test]
+    fn from_lua_breaks_a_self_referential_cycle() {
+        let lua = Lua::new();
+        let table = lua.create_table().unwrap();
+        table.set("self", table.clone()).unwrap();
+        let value = LuaValue::Table(table);
+
+        // Must return rather than overflow the stack; the cyclic branch
+        // collapses to opaque debug text like any other unsupported value.
+        let result = TableValue::from_lua(&value).unwrap();
+        assert!(matches!(result, TableValue::Table(_)));
+    }
+
+    #[test]
+    fn from_lua_caps_arbitrarily_deep_tables() {
+        let lua = Lua::new();
+        lua.globals()
+            .set(
+                "make_deep",
+                lua.create_function(|lua, depth: usize| {
+                    let mut table = lua.create_table()?;
+                    for _ in 0..depth {
+                        let outer = lua.create_table()?;
+                        outer.set("next", table)?;
+                        table = outer;
+                    }
+                    Ok(table)
+                })
+                .unwrap(),
+            )
+            .unwrap();
+        let deep: LuaValue = lua.load("return make_deep(1000)").eval().unwrap();
+
+        // Must return rather than overflow the stack.
+        TableValue::from_lua(&deep).unwrap();
+    }
+}
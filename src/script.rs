@@ -1,12 +1,14 @@
 use std::collections::BTreeMap;
-use std::sync::Arc;
+use std::path::Path;
+use std::sync::{mpsc, Arc};
 
 use egui::mutex::Mutex;
 use indexmap::IndexMap;
 use mlua::prelude::*;
+use notify::{EventKind, RecommendedWatcher, RecursiveMode, Watcher};
 use once_cell::sync::Lazy;
 
-use crate::node::{Control, NodePrototype, Pin};
+use crate::node::{Control, NodePrototype, Pin, TableValue};
 
 pub static REGISTRY: Lazy<Mutex<BTreeMap<String, Arc<NodePrototype>>>> =
     Lazy::new(|| Mutex::new(BTreeMap::new()));
@@ -14,6 +16,16 @@ pub static REGISTRY: Lazy<Mutex<BTreeMap<String, Arc<NodePrototype>>>> =
 pub static CATEGORY: Lazy<Mutex<BTreeMap<String, NodeEntry>>> =
     Lazy::new(|| Mutex::new(BTreeMap::new()));
 
+/// Scratch registry that `register_node` fills while scripts are (re)loading.
+/// Only swapped into `REGISTRY`/`CATEGORY` once every script has loaded
+/// successfully, so a single broken script can't wipe out the node types
+/// other, unrelated scripts already registered.
+static PENDING_REGISTRY: Lazy<Mutex<BTreeMap<String, Arc<NodePrototype>>>> =
+    Lazy::new(|| Mutex::new(BTreeMap::new()));
+
+static PENDING_CATEGORY: Lazy<Mutex<BTreeMap<String, NodeEntry>>> =
+    Lazy::new(|| Mutex::new(BTreeMap::new()));
+
 pub enum NodeEntry {
     Node(Arc<NodePrototype>),
     Category(BTreeMap<String, NodeEntry>),
@@ -47,13 +59,55 @@ impl NodeEntry {
 pub fn init_lua() -> LuaResult<Lua> {
     let lua = Lua::new();
     init_global(&lua)?;
+    reload_scripts(&lua)?;
+    Ok(lua)
+}
+
+/// Loads every `.luau` script into `register_node`'s scratch registry, then
+/// swaps it into `REGISTRY`/`CATEGORY` only if every script loaded
+/// successfully. On error the previous `REGISTRY`/`CATEGORY` are left
+/// untouched, so a single broken script doesn't take down the node types
+/// that were already registered.
+pub fn reload_scripts(lua: &Lua) -> LuaResult<()> {
+    PENDING_REGISTRY.lock().clear();
+    PENDING_CATEGORY.lock().clear();
 
     for script in glob::glob("scripts/**/*.luau").unwrap() {
         let script = script.unwrap_or_else(|e| panic!("{e}"));
         lua.load(script).exec()?;
     }
 
-    Ok(lua)
+    *REGISTRY.lock() = std::mem::take(&mut *PENDING_REGISTRY.lock());
+    *CATEGORY.lock() = std::mem::take(&mut *PENDING_CATEGORY.lock());
+    Ok(())
+}
+
+/// Watches `scripts/` for changes, sending a message on the returned channel
+/// for every relevant event. `Lua` can't be cloned or shared across threads
+/// (it's `Send` but not `Sync`), so this doesn't reload scripts itself on the
+/// watcher thread — the caller owns the `Lua` and is expected to call
+/// `reload_scripts` and rebind its placed nodes once notified. The returned
+/// watcher must be kept alive for as long as reloading should happen.
+pub fn watch_scripts() -> notify::Result<(RecommendedWatcher, mpsc::Receiver<()>)> {
+    let (reload_tx, reload_rx) = mpsc::channel();
+
+    let mut watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+        let event = match event {
+            Ok(event) => event,
+            Err(e) => return crate::report_error(&e),
+        };
+        if !matches!(
+            event.kind,
+            EventKind::Create(_) | EventKind::Modify(_) | EventKind::Remove(_)
+        ) {
+            return;
+        }
+
+        reload_tx.send(()).ok();
+    })?;
+
+    watcher.watch(Path::new("scripts"), RecursiveMode::Recursive)?;
+    Ok((watcher, reload_rx))
 }
 
 fn init_global(lua: &Lua) -> LuaResult<()> {
@@ -64,10 +118,17 @@ fn init_global(lua: &Lua) -> LuaResult<()> {
 
     // pins
     globals.set("float", lua.create_function(float)?)?;
+    globals.set("vector2", lua.create_function(vector2)?)?;
+    globals.set("vector3", lua.create_function(vector3)?)?;
+    globals.set("vector4", lua.create_function(vector4)?)?;
+    globals.set("any", lua.create_function(any)?)?;
 
     // controls
     globals.set("slider", lua.create_function(slider)?)?;
     globals.set("show_float", lua.create_function(show_float)?)?;
+    globals.set("vector_input", lua.create_function(vector_input)?)?;
+    globals.set("show_vector", lua.create_function(show_vector)?)?;
+    globals.set("show_value", lua.create_function(show_value)?)?;
 
     Ok(())
 }
@@ -79,12 +140,18 @@ fn register_node(lua: &Lua, args: LuaTable) -> LuaResult<()> {
     let inputs: Option<LuaTable> = args.try_get(lua, "inputs")?;
     let outputs: Option<LuaTable> = args.try_get(lua, "outputs")?;
     let controls: Option<LuaTable> = args.try_get(lua, "controls")?;
+    let state: Option<LuaValue> = args.try_get(lua, "state")?;
     let run: LuaFunction = args.get("run")?;
 
     let inputs = inputs.map_or_else(|| Ok(IndexMap::default()), |t| t.pairs().collect())?;
     let outputs = outputs.map_or_else(|| Ok(IndexMap::default()), |t| t.pairs().collect())?;
     let controls = controls.map_or_else(|| Ok(IndexMap::default()), |t| t.pairs().collect())?;
-    let run = lua.create_registry_value(run)?;
+    let state = state.map(|v| TableValue::from_lua(&v)).transpose()?;
+    // `RegistryKey` isn't `Clone`, and the boxed `run` closure below must be
+    // callable (and `Send + Sync`) many times over, each call producing a
+    // future that owns everything it touches rather than borrowing from the
+    // closure's captured state — so share it behind an `Arc` instead.
+    let run = Arc::new(lua.create_registry_value(run)?);
 
     for key in controls.keys() {
         if inputs.contains_key(key) {
@@ -101,16 +168,22 @@ fn register_node(lua: &Lua, args: LuaTable) -> LuaResult<()> {
         inputs,
         outputs,
         controls,
-        run: Box::new(move |lua, inputs| {
-            let run: LuaFunction = lua.registry_value(&run)?;
-            let result = run.call::<_, LuaTable>(inputs)?;
-            Ok(result)
+        state,
+        run: Box::new(move |lua, inputs, state| {
+            let run = run.clone();
+            Box::pin(async move {
+                let run: LuaFunction = lua.registry_value(&run)?;
+                match state {
+                    Some(state) => run.call_async::<_, LuaTable>((inputs, state)).await,
+                    None => run.call_async::<_, LuaTable>(inputs).await,
+                }
+            })
         }),
     });
 
-    REGISTRY.lock().insert(id, prototype.clone());
+    PENDING_REGISTRY.lock().insert(id, prototype.clone());
 
-    let mut category = CATEGORY.lock();
+    let mut category = PENDING_CATEGORY.lock();
     let mut category = &mut *category;
     let name = name.split("::").collect::<Vec<_>>();
     for name in name.iter().take(name.len() - 1) {
@@ -131,6 +204,22 @@ fn float(_lua: &Lua, _args: ()) -> LuaResult<Pin> {
     Ok(Pin::Float)
 }
 
+fn vector2(_lua: &Lua, _args: ()) -> LuaResult<Pin> {
+    Ok(Pin::Vec2)
+}
+
+fn vector3(_lua: &Lua, _args: ()) -> LuaResult<Pin> {
+    Ok(Pin::Vec3)
+}
+
+fn vector4(_lua: &Lua, _args: ()) -> LuaResult<Pin> {
+    Ok(Pin::Vec4)
+}
+
+fn any(_lua: &Lua, _args: ()) -> LuaResult<Pin> {
+    Ok(Pin::Any)
+}
+
 fn slider(_lua: &Lua, args: LuaTable) -> LuaResult<Control> {
     let min: f32 = args.get("min")?;
     let max: f32 = args.get("max")?;
@@ -144,6 +233,34 @@ fn show_float(_lua: &Lua, args: LuaTable) -> LuaResult<Control> {
     Ok(Control::ShowFloat { value })
 }
 
+fn vector_input(_lua: &Lua, args: LuaTable) -> LuaResult<Control> {
+    let (value, dim) = vector_components(args)?;
+    Ok(Control::VectorInput { value, dim })
+}
+
+fn show_vector(_lua: &Lua, args: LuaTable) -> LuaResult<Control> {
+    let (value, dim) = vector_components(args)?;
+    Ok(Control::ShowVector { value, dim })
+}
+
+fn show_value(_lua: &Lua, args: LuaTable) -> LuaResult<Control> {
+    let value: LuaValue = args.get("value")?;
+    Ok(Control::ShowValue {
+        value: TableValue::from_lua(&value)?,
+    })
+}
+
+fn vector_components(args: LuaTable) -> LuaResult<([f32; 4], u8)> {
+    let components: Vec<f32> = args.get("value")?;
+    if !(2..=4).contains(&components.len()) {
+        return Err(LuaError::external("vector value must have 2 to 4 components"));
+    }
+
+    let mut value = [0.0; 4];
+    value[..components.len()].copy_from_slice(&components);
+    Ok((value, components.len() as u8))
+}
+
 trait LuaTableExt<'a> {
     fn try_get<K, V>(&self, lua: &'a Lua, key: K) -> LuaResult<Option<V>>
     where